@@ -2,7 +2,8 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use std::ffi::CString;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
 use std::io::Write;
 use std::marker::PhantomData;
 use std::mem::MaybeUninit;
@@ -110,6 +111,7 @@ extern "C" {
     pub static mut files_meta: hg_notes_tree;
 
     fn combine_notes_ignore(cur_oid: *mut object_id, new_oid: *const object_id) -> c_int;
+    fn combine_notes_overwrite(cur_oid: *mut object_id, new_oid: *const object_id) -> c_int;
 
     fn cinnabar_init_notes(
         notes: *mut cinnabar_notes_tree,
@@ -134,6 +136,21 @@ extern "C" {
         len: usize,
     ) -> *const object_id;
 
+    // Like get_abbrev_note, but invokes cb for every annotated object whose
+    // id starts with the first len hex digits of `oid`, instead of stopping at
+    // the first match.
+    fn get_abbrev_notes(
+        notes: *mut cinnabar_notes_tree,
+        oid: *const object_id,
+        len: usize,
+        cb: unsafe extern "C" fn(
+            oid: *const object_id,
+            note_oid: *const object_id,
+            cb_data: *mut c_void,
+        ) -> c_int,
+        cb_data: *mut c_void,
+    );
+
     fn cinnabar_for_each_note(
         notes: *mut cinnabar_notes_tree,
         flags: c_int,
@@ -146,6 +163,23 @@ extern "C" {
         cb_data: *mut c_void,
     ) -> c_int;
 
+    // Like cinnabar_for_each_note, but skips any subtree whose fanout path
+    // cannot be a prefix of (nor prefixed by) `prefix`, instead of walking
+    // the whole notes tree.
+    fn cinnabar_for_each_note_in_prefix(
+        notes: *mut cinnabar_notes_tree,
+        prefix: *const c_char,
+        prefix_len: usize,
+        flags: c_int,
+        cb: unsafe extern "C" fn(
+            oid: *const object_id,
+            note_oid: *const object_id,
+            note_path: *const c_char,
+            cb_data: *mut c_void,
+        ) -> c_int,
+        cb_data: *mut c_void,
+    ) -> c_int;
+
     fn cinnabar_add_note(
         notes: *mut cinnabar_notes_tree,
         object_oid: *const object_id,
@@ -162,36 +196,187 @@ extern "C" {
         result: *mut object_id,
         mode: c_uint,
     ) -> c_int;
+
+    fn has_object_file(oid: *const object_id) -> c_int;
 }
 
 const NOTES_INIT_EMPTY: c_int = 1;
 
+/// Combine strategy used when `add_note` is called for a key that already
+/// has a note, modeled on git's own notes merge strategies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CombinePolicy {
+    /// Keep the existing note (git's `combine_notes_ignore`). The default.
+    Ignore,
+    /// The incoming note replaces the existing one.
+    Overwrite,
+    /// Keep the existing note, like `Ignore`, but record a conflict when
+    /// the incoming note differs so the caller can report it instead of
+    /// silently losing the divergence.
+    Verify,
+}
+
+impl Default for CombinePolicy {
+    fn default() -> Self {
+        CombinePolicy::Ignore
+    }
+}
+
+/// Returned by `set_combine_policy` when the tree has already been lazily
+/// initialized, at which point its combine function is baked in and can no
+/// longer be changed.
+#[derive(Clone, Copy, Debug)]
+pub struct NotesTreeAlreadyInitialized;
+
+impl std::fmt::Display for NotesTreeAlreadyInitialized {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cannot change the combine policy of a notes tree after it has been initialized"
+        )
+    }
+}
+
+impl std::error::Error for NotesTreeAlreadyInitialized {}
+
+type CombineConflict = (*mut c_void, GitObjectId, GitObjectId, GitObjectId);
+
+thread_local! {
+    // The (tree, key) the combine trampoline should blame a divergence on,
+    // set by add_note right before calling into cinnabar_add_note.
+    static COMBINE_CONTEXT: RefCell<Option<(*mut c_void, GitObjectId)>> = RefCell::new(None);
+    // (tree, key, old, new) tuples stashed by combine_notes_verify, drained
+    // by the wrapper's take_conflicts.
+    static COMBINE_CONFLICTS: RefCell<Vec<CombineConflict>> = RefCell::new(Vec::new());
+}
+
+unsafe extern "C" fn combine_notes_verify(
+    cur_oid: *mut object_id,
+    new_oid: *const object_id,
+) -> c_int {
+    let cur = GitObjectId::from(cur_oid.as_ref().unwrap().clone());
+    let new = GitObjectId::from(new_oid.as_ref().unwrap().clone());
+    if cur != new {
+        if let Some((tree, key)) = COMBINE_CONTEXT.with(|c| c.borrow().clone()) {
+            COMBINE_CONFLICTS.with(|c| c.borrow_mut().push((tree, key, cur, new)));
+        }
+    }
+    // Keep the existing note, same as Ignore, now that the conflict is on record.
+    0
+}
+
+fn combine_notes_fn_for(
+    policy: CombinePolicy,
+) -> unsafe extern "C" fn(*mut object_id, *const object_id) -> c_int {
+    match policy {
+        CombinePolicy::Ignore => combine_notes_ignore,
+        CombinePolicy::Overwrite => combine_notes_overwrite,
+        CombinePolicy::Verify => combine_notes_verify,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum KnownNotesTree {
+    Git2Hg,
+    Hg2Git,
+    FilesMeta,
+}
+
+unsafe fn known_notes_tree(t: *const cinnabar_notes_tree) -> Option<KnownNotesTree> {
+    if ptr::eq(t, &git2hg.0) {
+        Some(KnownNotesTree::Git2Hg)
+    } else if ptr::eq(t, &hg2git.0) {
+        Some(KnownNotesTree::Hg2Git)
+    } else if ptr::eq(t, &files_meta.0) {
+        Some(KnownNotesTree::FilesMeta)
+    } else {
+        None
+    }
+}
+
+static mut GIT2HG_COMBINE_POLICY: CombinePolicy = CombinePolicy::Ignore;
+static mut HG2GIT_COMBINE_POLICY: CombinePolicy = CombinePolicy::Ignore;
+static mut FILES_META_COMBINE_POLICY: CombinePolicy = CombinePolicy::Ignore;
+
+unsafe fn combine_policy_for(t: *const cinnabar_notes_tree) -> CombinePolicy {
+    match known_notes_tree(t) {
+        Some(KnownNotesTree::Git2Hg) => GIT2HG_COMBINE_POLICY,
+        Some(KnownNotesTree::Hg2Git) => HG2GIT_COMBINE_POLICY,
+        Some(KnownNotesTree::FilesMeta) => FILES_META_COMBINE_POLICY,
+        None => CombinePolicy::Ignore,
+    }
+}
+
+unsafe fn set_combine_policy_for(t: *const cinnabar_notes_tree, policy: CombinePolicy) {
+    match known_notes_tree(t) {
+        Some(KnownNotesTree::Git2Hg) => GIT2HG_COMBINE_POLICY = policy,
+        Some(KnownNotesTree::Hg2Git) => HG2GIT_COMBINE_POLICY = policy,
+        Some(KnownNotesTree::FilesMeta) => FILES_META_COMBINE_POLICY = policy,
+        None => die!("Unknown notes tree"),
+    }
+}
+
 unsafe fn ensure_notes(t: *mut cinnabar_notes_tree) {
     if notes_initialized(t) == 0 {
         let oid;
         let mut flags = 0;
-        if ptr::eq(t, &git2hg.0) {
-            oid = git2hg_oid.clone();
-        } else if ptr::eq(t, &hg2git.0) {
-            oid = hg2git_oid.clone();
-        } else if ptr::eq(t, &files_meta.0) {
-            oid = files_meta_oid.clone();
-            if metadata_flags & FILES_META == 0 {
-                flags = NOTES_INIT_EMPTY;
+        match known_notes_tree(t) {
+            Some(KnownNotesTree::Git2Hg) => oid = git2hg_oid.clone(),
+            Some(KnownNotesTree::Hg2Git) => oid = hg2git_oid.clone(),
+            Some(KnownNotesTree::FilesMeta) => {
+                oid = files_meta_oid.clone();
+                if metadata_flags & FILES_META == 0 {
+                    flags = NOTES_INIT_EMPTY;
+                }
             }
-        } else {
-            die!("Unknown notes tree");
+            None => die!("Unknown notes tree"),
         }
         let oid = GitObjectId::from(oid);
         if oid.is_null() {
             flags = NOTES_INIT_EMPTY;
         }
         let oid = CString::new(oid.to_string()).unwrap();
-        cinnabar_init_notes(t, oid.as_ptr(), combine_notes_ignore, flags);
+        let combine_notes_fn = combine_notes_fn_for(combine_policy_for(t));
+        cinnabar_init_notes(t, oid.as_ptr(), combine_notes_fn, flags);
     }
 }
 
 fn for_each_note_in<F: FnMut(GitObjectId, GitObjectId)>(notes: &mut cinnabar_notes_tree, mut f: F) {
+    for_each_note_in_with_path(notes, |o, n, _path| f(o, n));
+}
+
+// Like for_each_note_in, but also yields the fanout path (e.g. "ab/cd...")
+// the note is stored under.
+fn for_each_note_in_with_path<F: FnMut(GitObjectId, GitObjectId, &[u8])>(
+    notes: &mut cinnabar_notes_tree,
+    mut f: F,
+) {
+    unsafe extern "C" fn each_note_cb<F: FnMut(GitObjectId, GitObjectId, &[u8])>(
+        oid: *const object_id,
+        note_oid: *const object_id,
+        note_path: *const c_char,
+        cb_data: *mut c_void,
+    ) -> c_int {
+        let cb = (cb_data as *mut F).as_mut().unwrap();
+        let o = oid.as_ref().unwrap().clone().into();
+        let n = note_oid.as_ref().unwrap().clone().into();
+        let path = CStr::from_ptr(note_path).to_bytes();
+        cb(o, n, path);
+        0
+    }
+
+    unsafe {
+        cinnabar_for_each_note(notes, 0, each_note_cb::<F>, &mut f as *mut F as *mut c_void);
+    }
+}
+
+// Like for_each_note_in, but short-circuits subtrees whose
+// fanout path cannot match `prefix`.
+fn for_each_note_in_prefix<F: FnMut(GitObjectId, GitObjectId)>(
+    notes: &mut cinnabar_notes_tree,
+    prefix: &[u8],
+    mut f: F,
+) {
     unsafe extern "C" fn each_note_cb<F: FnMut(GitObjectId, GitObjectId)>(
         oid: *const object_id,
         note_oid: *const object_id,
@@ -206,7 +391,14 @@ fn for_each_note_in<F: FnMut(GitObjectId, GitObjectId)>(notes: &mut cinnabar_not
     }
 
     unsafe {
-        cinnabar_for_each_note(notes, 0, each_note_cb::<F>, &mut f as *mut F as *mut c_void);
+        cinnabar_for_each_note_in_prefix(
+            notes,
+            prefix.as_ptr() as *const c_char,
+            prefix.len(),
+            0,
+            each_note_cb::<F>,
+            &mut f as *mut F as *mut c_void,
+        );
     }
 }
 
@@ -242,6 +434,7 @@ unsafe fn add_note_hg(
     let git_oid =
         GitObjectId::from_raw_bytes(HgObjectId::from(oid.as_ref().unwrap().clone()).as_raw_bytes())
             .unwrap();
+    COMBINE_CONTEXT.with(|c| *c.borrow_mut() = Some((notes as *mut c_void, git_oid)));
     cinnabar_add_note(notes, &git_oid.into(), note_oid)
 }
 
@@ -311,13 +504,70 @@ impl git_notes_tree {
         for_each_note_in(&mut self.0, f);
     }
 
+    /// Like `for_each`, but also yields the fanout path each note is
+    /// stored under (e.g. `"ab/cd..."`).
+    pub fn for_each_with_path<F: FnMut(GitObjectId, GitObjectId, &[u8])>(&mut self, f: F) {
+        unsafe {
+            ensure_notes(&mut self.0);
+        }
+        for_each_note_in_with_path(&mut self.0, f);
+    }
+
+    /// Like `for_each`, but only visits notes whose key starts with
+    /// `prefix`, skipping the fanout subtrees that cannot contain a match.
+    pub fn for_each_with_prefix<F: FnMut(GitObjectId, GitObjectId)>(
+        &mut self,
+        prefix: &[u8],
+        f: F,
+    ) {
+        unsafe {
+            ensure_notes(&mut self.0);
+        }
+        for_each_note_in_prefix(&mut self.0, prefix, f);
+    }
+
+    /// Remove every note whose annotated commit is no longer in the git
+    /// object database, analogous to `git notes prune`. Returns the number
+    /// of notes removed.
+    pub fn prune(&mut self) -> usize {
+        unsafe {
+            ensure_notes(&mut self.0);
+        }
+        let mut dead = Vec::new();
+        self.for_each(|oid, _note| {
+            if unsafe { has_object_file(&oid.into()) } == 0 {
+                dead.push(oid);
+            }
+        });
+        let count = dead.len();
+        for oid in dead {
+            self.remove_note(oid);
+        }
+        count
+    }
+
     pub fn add_note(&mut self, oid: GitObjectId, note_oid: GitObjectId) {
         unsafe {
             ensure_notes(&mut self.0);
+            let tree = &mut self.0 as *mut cinnabar_notes_tree as *mut c_void;
+            COMBINE_CONTEXT.with(|c| *c.borrow_mut() = Some((tree, oid)));
             cinnabar_add_note(&mut self.0, &oid.into(), &note_oid.into());
         }
     }
 
+    /// Copy the note for `from` onto `to`, like `git notes copy`, so
+    /// grafted/rewritten objects can reuse an existing mapping instead of
+    /// recomputing it. Returns whether `from` had a note to copy.
+    pub fn copy_note(&mut self, from: GitObjectId, to: GitObjectId) -> bool {
+        match self.get_note(from) {
+            Some(note_oid) => {
+                self.add_note(to, note_oid);
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn remove_note(&mut self, oid: GitObjectId) {
         unsafe {
             ensure_notes(&mut self.0);
@@ -325,6 +575,39 @@ impl git_notes_tree {
         }
     }
 
+    /// Select the combine policy applied the next time a note is added for
+    /// a key that already has one. Must be called before the tree is first
+    /// used (before any `get_note`/`add_note`/... on it), as it only takes
+    /// effect when the tree is lazily initialized; returns `Err` instead of
+    /// aborting if that window has already passed.
+    pub fn set_combine_policy(
+        &mut self,
+        policy: CombinePolicy,
+    ) -> Result<(), NotesTreeAlreadyInitialized> {
+        unsafe {
+            if notes_initialized(&self.0) != 0 {
+                return Err(NotesTreeAlreadyInitialized);
+            }
+            set_combine_policy_for(&self.0, policy);
+        }
+        Ok(())
+    }
+
+    /// Drain the conflicts recorded by the `Verify` combine policy since
+    /// the last call, as (object, old note, new note) tuples.
+    pub fn take_conflicts(&mut self) -> Vec<(GitObjectId, GitObjectId, GitObjectId)> {
+        let tree = &mut self.0 as *mut cinnabar_notes_tree as *mut c_void;
+        COMBINE_CONFLICTS.with(|c| {
+            let mut conflicts = c.borrow_mut();
+            let (mine, rest): (Vec<_>, Vec<_>) =
+                conflicts.drain(..).partition(|(t, ..)| ptr::eq(*t, tree));
+            *conflicts = rest;
+            mine.into_iter()
+                .map(|(_, key, old, new)| (key, old, new))
+                .collect()
+        })
+    }
+
     pub fn done(&mut self) {
         unsafe {
             if notes_initialized(&self.0) != 0 {
@@ -376,6 +659,60 @@ impl hg_notes_tree {
         }
     }
 
+    /// Resolve an abbreviated hg id to every annotated object whose full id
+    /// starts with it, up to `limit`, so callers can detect and report
+    /// ambiguity the way git does for a too-short sha.
+    pub fn get_notes_abbrev<H: ObjectId + Into<hg_object_id>>(
+        &mut self,
+        oid: Abbrev<H>,
+        limit: usize,
+    ) -> Vec<(HgObjectId, GitObjectId)> {
+        unsafe extern "C" fn each_abbrev_cb(
+            oid: *const object_id,
+            note_oid: *const object_id,
+            cb_data: *mut c_void,
+        ) -> c_int {
+            let (result, limit) = (cb_data as *mut (Vec<(HgObjectId, GitObjectId)>, usize))
+                .as_mut()
+                .unwrap();
+            if result.len() >= *limit {
+                return 1;
+            }
+            let key = GitObjectId::from(oid.as_ref().unwrap().clone());
+            let h = HgObjectId::from_raw_bytes(key.as_raw_bytes()).unwrap();
+            let g = note_oid.as_ref().unwrap().clone().into();
+            result.push((h, g));
+            0
+        }
+
+        unsafe {
+            ensure_notes(&mut self.0);
+            let len = oid.len();
+            let git_oid: object_id = GitObjectId::from_raw_bytes(oid.as_object_id().as_raw_bytes())
+                .unwrap()
+                .into();
+            if len == 40 {
+                let key = HgObjectId::from_raw_bytes(
+                    GitObjectId::from(git_oid.clone()).as_raw_bytes(),
+                )
+                .unwrap();
+                return cinnabar_get_note(&mut self.0, &git_oid)
+                    .as_ref()
+                    .map(|note| vec![(key, note.clone().into())])
+                    .unwrap_or_default();
+            }
+            let mut cb_data = (Vec::new(), limit);
+            get_abbrev_notes(
+                &mut self.0,
+                &git_oid,
+                len,
+                each_abbrev_cb,
+                &mut cb_data as *mut (Vec<(HgObjectId, GitObjectId)>, usize) as *mut c_void,
+            );
+            cb_data.0
+        }
+    }
+
     pub fn for_each<F: FnMut(HgObjectId, GitObjectId)>(&mut self, mut f: F) {
         for_each_note_in(&mut self.0, |h, g| {
             let h = HgObjectId::from_raw_bytes(h.as_raw_bytes()).unwrap();
@@ -383,16 +720,74 @@ impl hg_notes_tree {
         });
     }
 
+    /// Like `for_each`, but also yields the fanout path each note is
+    /// stored under (e.g. `"ab/cd..."`).
+    pub fn for_each_with_path<F: FnMut(HgObjectId, GitObjectId, &[u8])>(&mut self, mut f: F) {
+        unsafe {
+            ensure_notes(&mut self.0);
+        }
+        for_each_note_in_with_path(&mut self.0, |h, g, path| {
+            let h = HgObjectId::from_raw_bytes(h.as_raw_bytes()).unwrap();
+            f(h, g, path);
+        });
+    }
+
+    /// Like `for_each`, but only visits notes whose hg id starts with
+    /// `prefix`, skipping the fanout subtrees that cannot contain a match.
+    pub fn for_each_with_prefix<F: FnMut(HgObjectId, GitObjectId)>(
+        &mut self,
+        prefix: &[u8],
+        mut f: F,
+    ) {
+        unsafe {
+            ensure_notes(&mut self.0);
+        }
+        for_each_note_in_prefix(&mut self.0, prefix, |h, g| {
+            let h = HgObjectId::from_raw_bytes(h.as_raw_bytes()).unwrap();
+            f(h, g);
+        });
+    }
+
+    /// Remove every note whose mapped git object is no longer in the git
+    /// object database, analogous to `git notes prune`. Returns the number
+    /// of notes removed.
+    pub fn prune(&mut self) -> usize {
+        unsafe {
+            ensure_notes(&mut self.0);
+        }
+        let mut dead = Vec::new();
+        self.for_each(|oid, note| {
+            if unsafe { has_object_file(&note.into()) } == 0 {
+                dead.push(oid);
+            }
+        });
+        let count = dead.len();
+        for oid in dead {
+            self.remove_note(oid);
+        }
+        count
+    }
+
     pub fn add_note(&mut self, oid: HgObjectId, note_oid: GitObjectId) {
         unsafe {
             ensure_notes(&mut self.0);
-            cinnabar_add_note(
-                &mut self.0,
-                &GitObjectId::from_raw_bytes(oid.as_raw_bytes())
-                    .unwrap()
-                    .into(),
-                &note_oid.into(),
-            );
+            let key = GitObjectId::from_raw_bytes(oid.as_raw_bytes()).unwrap();
+            let tree = &mut self.0 as *mut cinnabar_notes_tree as *mut c_void;
+            COMBINE_CONTEXT.with(|c| *c.borrow_mut() = Some((tree, key)));
+            cinnabar_add_note(&mut self.0, &key.into(), &note_oid.into());
+        }
+    }
+
+    /// Copy the note for `from` onto `to`, like `git notes copy`, so
+    /// grafted/rewritten objects can reuse an existing mapping instead of
+    /// recomputing it. Returns whether `from` had a note to copy.
+    pub fn copy_note(&mut self, from: HgObjectId, to: HgObjectId) -> bool {
+        match self.get_note(from) {
+            Some(note_oid) => {
+                self.add_note(to, note_oid);
+                true
+            }
+            None => false,
         }
     }
 
@@ -403,6 +798,45 @@ impl hg_notes_tree {
         }
     }
 
+    /// Select the combine policy applied the next time a note is added for
+    /// a key that already has one. Must be called before the tree is first
+    /// used (before any `get_note`/`add_note`/... on it), as it only takes
+    /// effect when the tree is lazily initialized; returns `Err` instead of
+    /// aborting if that window has already passed.
+    pub fn set_combine_policy(
+        &mut self,
+        policy: CombinePolicy,
+    ) -> Result<(), NotesTreeAlreadyInitialized> {
+        unsafe {
+            if notes_initialized(&self.0) != 0 {
+                return Err(NotesTreeAlreadyInitialized);
+            }
+            set_combine_policy_for(&self.0, policy);
+        }
+        Ok(())
+    }
+
+    /// Drain the conflicts recorded by the `Verify` combine policy since
+    /// the last call, as (hg id, old note, new note) tuples.
+    pub fn take_conflicts(&mut self) -> Vec<(HgObjectId, GitObjectId, GitObjectId)> {
+        let tree = &mut self.0 as *mut cinnabar_notes_tree as *mut c_void;
+        COMBINE_CONFLICTS.with(|c| {
+            let mut conflicts = c.borrow_mut();
+            let (mine, rest): (Vec<_>, Vec<_>) =
+                conflicts.drain(..).partition(|(t, ..)| ptr::eq(*t, tree));
+            *conflicts = rest;
+            mine.into_iter()
+                .map(|(_, key, old, new)| {
+                    (
+                        HgObjectId::from_raw_bytes(key.as_raw_bytes()).unwrap(),
+                        old,
+                        new,
+                    )
+                })
+                .collect()
+        })
+    }
+
     pub fn done(&mut self) {
         unsafe {
             if notes_initialized(&self.0) != 0 {